@@ -9,12 +9,15 @@ mod apis;
 mod exec;
 mod incluster_config;
 mod kube_config;
+mod oidc;
 mod utils;
 
 use base64;
 use failure::ResultExt;
+use secrecy::{ExposeSecret, SecretString};
 use crate::{Error, ErrorKind, Result};
 use reqwest::{header, Certificate, Client, ClientBuilder, Identity};
+use std::time::Duration;
 
 use self::kube_config::KubeConfigLoader;
 
@@ -62,6 +65,39 @@ pub struct ConfigOptions {
     pub context: Option<String>,
     pub cluster: Option<String>,
     pub user: Option<String>,
+
+    /// When set, credentials refreshed while loading (e.g. an OIDC `id-token`) are written
+    /// back into the originating kubeconfig so subsequent runs can reuse them. The file is
+    /// rewritten from the parsed `Config`, discarding comments and unmodeled fields.
+    pub persist_config: bool,
+
+    /// Additional trusted root certificates to merge with the ones taken from the kubeconfig.
+    pub root_certs: Vec<Certificate>,
+    /// Overall request timeout applied to the reqwest client.
+    pub timeout: Option<Duration>,
+    /// Explicit proxy URL. When unset, `HTTPS_PROXY` from the environment is used; `NO_PROXY`
+    /// is honored in both cases.
+    pub proxy: Option<String>,
+}
+
+impl ConfigOptions {
+    /// Trust an extra root certificate in addition to those from the kubeconfig cluster.
+    pub fn add_root_certificate(mut self, cert: Certificate) -> Self {
+        self.root_certs.push(cert);
+        self
+    }
+
+    /// Set the overall request timeout for the reqwest client.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Route requests through the given proxy, overriding the `HTTPS_PROXY` environment.
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
 }
 
 /// Returns a config which includes authentication and cluster information from kubeconfig file.
@@ -84,6 +120,45 @@ pub fn load_kube_config_with(options: ConfigOptions) -> Result<Configuration> {
     ))
 }
 
+/// Loads and merges every kubeconfig referenced by `KUBECONFIG`.
+///
+/// Following the client-go convention, the files are visited in order and the first one to define
+/// a given named cluster/user/context (and the top-level `current-context`/preferences) wins.
+fn load_merged_kubeconfig() -> Result<apis::Config> {
+    let paths = utils::find_kubeconfig()
+        .context(ErrorKind::KubeConfig("Unable to find a kubeconfig".into()))?;
+
+    let (first, rest) = paths.split_first().ok_or_else(|| {
+        ErrorKind::KubeConfig("KUBECONFIG did not reference any kubeconfig files".into())
+    })?;
+
+    let mut merged = apis::Config::load_config(first).with_context(|_| {
+        ErrorKind::KubeConfig(format!("Unable to load kubeconfig {}", first.display()))
+    })?;
+    for path in rest {
+        let next = apis::Config::load_config(path).with_context(|_| {
+            ErrorKind::KubeConfig(format!("Unable to load kubeconfig {}", path.display()))
+        })?;
+        merged.merge(next);
+    }
+    Ok(merged)
+}
+
+/// Builds a reqwest `Identity` from a PEM client certificate/key pair, via a PKCS#12 bundle.
+fn identity_from_pem(cert: &str, key: &str) -> Result<Identity> {
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::PKey;
+    use openssl::x509::X509;
+
+    let x509 = X509::from_pem(cert.as_bytes()).context(ErrorKind::SslError)?;
+    let pkey = PKey::private_key_from_pem(key.as_bytes()).context(ErrorKind::SslError)?;
+    let p12 = Pkcs12::builder()
+        .build(" ", "kubeconfig", &pkey, &x509)
+        .context(ErrorKind::SslError)?;
+    Identity::from_pkcs12_der(&p12.to_der().context(ErrorKind::SslError)?, " ")
+        .context(ErrorKind::SslError)
+}
+
 /// Returns a client builder and config loader, based on the cluster information from the kubeconfig file.
 ///
 /// This allows to create your custom reqwest client for using with the cluster API.
@@ -98,13 +173,37 @@ pub fn load_kube_config_with(options: ConfigOptions) -> Result<Configuration> {
 /// let loader = client_builder_result.1;
 /// ```
 pub fn create_client_builder(options: ConfigOptions) -> Result<(ClientBuilder,KubeConfigLoader)> {
-    let kubeconfig = utils::find_kubeconfig()
-        .context(ErrorKind::KubeConfig("Unable to load file".into()))?;
+    let config = load_merged_kubeconfig()?;
+
+    let selected_user = options.user.clone();
+    let mut loader =
+        KubeConfigLoader::load(config, options.context, options.cluster, options.user)?;
 
-    let loader =
-        KubeConfigLoader::load(kubeconfig, options.context, options.cluster, options.user)?;
+    // An `auth-provider` block (currently only `oidc`) supplies a bearer token out of band: the
+    // cached `id-token` is reused while still valid and otherwise refreshed via the OIDC provider.
+    if loader.user.token.is_none() {
+        if let Some(provider) = loader.user.auth_provider.clone() {
+            if provider.name == "oidc" {
+                let refreshed = oidc::token(
+                    &provider,
+                    options.proxy.as_ref().map(String::as_str),
+                    options.timeout,
+                )?;
+                if options.persist_config && refreshed.refreshed {
+                    let user = selected_user
+                        .as_ref()
+                        .unwrap_or(&loader.current_context.user);
+                    oidc::persist(user, &refreshed)?;
+                }
+                loader.user.token = Some(SecretString::new(refreshed.id_token));
+            }
+        }
+    }
 
-    let token = match &loader.user.token {
+    // An exec plugin may return either a bearer token or a client certificate/key pair for
+    // mutual TLS (e.g. the AWS IAM authenticator); the two are mutually exclusive per response.
+    let mut exec_identity = None;
+    let token: Option<SecretString> = match &loader.user.token {
         Some(token) => Some(token.clone()),
         None => {
             if let Some(exec) = &loader.user.exec {
@@ -112,7 +211,20 @@ pub fn create_client_builder(options: ConfigOptions) -> Result<(ClientBuilder,Ku
                 let status = creds
                     .status
                     .ok_or_else(|| ErrorKind::KubeConfig("exec-plugin response did not contain a status".into()))?;
-                status.token
+                match (&status.client_certificate_data, &status.client_key_data) {
+                    (Some(cert), Some(key)) => {
+                        exec_identity = Some(identity_from_pem(cert, key)?);
+                        None
+                    }
+                    (None, None) => status.token.map(SecretString::new),
+                    _ => {
+                        return Err(ErrorKind::KubeConfig(
+                            "exec-plugin returned only one of clientCertificateData/clientKeyData"
+                                .into(),
+                        )
+                        .into())
+                    }
+                }
             } else {
                 None
             }
@@ -128,16 +240,21 @@ pub fn create_client_builder(options: ConfigOptions) -> Result<(ClientBuilder,Ku
             client_builder = client_builder.add_root_certificate(cert);
         }
     }
-    match loader.p12(" ") {
-        Ok(p12) => {
-            let req_p12 = Identity::from_pkcs12_der(&p12.to_der().context(ErrorKind::SslError)?, " ")
-                .context(ErrorKind::SslError)?;
-            client_builder = client_builder.identity(req_p12);
-        }
-        Err(_) => {
-            // last resort only if configs ask for it, and no client certs
-            if let Some(true) = loader.cluster.insecure_skip_tls_verify {
-                client_builder = client_builder.danger_accept_invalid_certs(true);
+    if let Some(identity) = exec_identity {
+        // Client certificate supplied by the exec plugin takes the place of any kubeconfig p12.
+        client_builder = client_builder.identity(identity);
+    } else {
+        match loader.p12(" ") {
+            Ok(p12) => {
+                let req_p12 = Identity::from_pkcs12_der(&p12.to_der().context(ErrorKind::SslError)?, " ")
+                    .context(ErrorKind::SslError)?;
+                client_builder = client_builder.identity(req_p12);
+            }
+            Err(_) => {
+                // last resort only if configs ask for it, and no client certs
+                if let Some(true) = loader.cluster.insecure_skip_tls_verify {
+                    client_builder = client_builder.danger_accept_invalid_certs(true);
+                }
             }
         }
     }
@@ -151,12 +268,12 @@ pub fn create_client_builder(options: ConfigOptions) -> Result<(ClientBuilder,Ku
         (Ok(token), _) => {
             headers.insert(
                 header::AUTHORIZATION,
-                header::HeaderValue::from_str(&format!("Bearer {}", token))
+                header::HeaderValue::from_str(&format!("Bearer {}", token.expose_secret()))
                     .context(ErrorKind::KubeConfig("Invalid bearer token".to_string()))?,
             );
         }
         (_, (Some(u), Some(p))) => {
-            let encoded = base64::encode(&format!("{}:{}", u, p));
+            let encoded = base64::encode(&format!("{}:{}", u, p.expose_secret()));
             headers.insert(
                 header::AUTHORIZATION,
                 header::HeaderValue::from_str(&format!("Basic {}", encoded))
@@ -166,10 +283,77 @@ pub fn create_client_builder(options: ConfigOptions) -> Result<(ClientBuilder,Ku
         _ => {}
     }
 
+    for cert in options.root_certs {
+        client_builder = client_builder.add_root_certificate(cert);
+    }
+    if let Some(timeout) = options.timeout {
+        client_builder = client_builder.timeout(timeout);
+    }
+    if let Some(proxy) = configure_proxy(options.proxy.as_ref().map(String::as_str))? {
+        client_builder = client_builder.proxy(proxy);
+    }
+
     Ok((client_builder.default_headers(headers), loader))
 
 }
 
+/// Resolves a proxy into a `reqwest::Proxy`, honoring `NO_PROXY`.
+///
+/// Uses the explicit URL when given, otherwise falls back to `HTTPS_PROXY` from the environment
+/// (reqwest 0.9 does not detect env proxies itself). Returns `None` when neither is set. The
+/// `NO_PROXY` matcher only understands `*` and host-suffix entries: IP/CIDR ranges (e.g.
+/// `10.0.0.0/8`) and per-entry ports are not supported.
+fn configure_proxy(explicit: Option<&str>) -> Result<Option<reqwest::Proxy>> {
+    let url = explicit
+        .map(str::to_string)
+        .or_else(|| env_var("HTTPS_PROXY").or_else(|| env_var("https_proxy")));
+    let url = match url {
+        Some(url) if !url.is_empty() => url,
+        _ => return Ok(None),
+    };
+
+    let no_proxy: Vec<String> = env_var("NO_PROXY")
+        .or_else(|| env_var("no_proxy"))
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let proxy_url = url
+        .parse::<reqwest::Url>()
+        .context(ErrorKind::KubeConfig("Invalid proxy URL".into()))?;
+    // A custom proxy lets us fall through to a direct connection for `NO_PROXY` hosts.
+    let proxy = reqwest::Proxy::custom(move |target| {
+        if no_proxy.iter().any(|suffix| bypasses(suffix, target)) {
+            None
+        } else {
+            Some(proxy_url.clone())
+        }
+    });
+    Ok(Some(proxy))
+}
+
+/// Returns the (non-empty) value of an environment variable, if set.
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.is_empty())
+}
+
+/// Matches a `NO_PROXY` entry against a target URL's host, supporting the conventional `*`
+/// wildcard and leading-dot/suffix forms (e.g. `.svc`, `example.com`).
+fn bypasses(entry: &str, target: &reqwest::Url) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    match target.host_str() {
+        Some(host) => {
+            let suffix = entry.trim_start_matches('.');
+            host == suffix || host.ends_with(&format!(".{}", suffix))
+        }
+        None => false,
+    }
+}
+
 /// Returns a config which is used by clients within pods on kubernetes.
 /// It will return an error if called from out of kubernetes cluster.
 ///
@@ -192,8 +376,8 @@ pub fn incluster_config() -> Result<Configuration> {
     let req_ca = Certificate::from_der(&ca.to_der().context(ErrorKind::SslError)?)
         .context(ErrorKind::SslError)?;
 
-    let token = incluster_config::load_token()
-        .context(ErrorKind::KubeConfig("Unable to load in cluster token".to_string()))?;
+    let token = SecretString::new(incluster_config::load_token()
+        .context(ErrorKind::KubeConfig("Unable to load in cluster token".to_string()))?);
 
     let default_ns = incluster_config::load_default_ns().context(ErrorKind::KubeConfig(
         "Unable to load incluster default namespace".to_string(),
@@ -202,7 +386,7 @@ pub fn incluster_config() -> Result<Configuration> {
     let mut headers = header::HeaderMap::new();
     headers.insert(
         header::AUTHORIZATION,
-        header::HeaderValue::from_str(&format!("Bearer {}", token))
+        header::HeaderValue::from_str(&format!("Bearer {}", token.expose_secret()))
             .context(ErrorKind::KubeConfig("Invalid bearer token".to_string()))?,
     );
 
@@ -232,3 +416,85 @@ pub use apis::{
     NamedContext,
     Context,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::bypasses;
+
+    fn url(s: &str) -> reqwest::Url {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bypasses_wildcard_matches_any_host() {
+        assert!(bypasses("*", &url("https://anything.example.com")));
+    }
+
+    #[test]
+    fn bypasses_dot_suffix_matches_domain_and_subdomains() {
+        assert!(bypasses(".example.com", &url("https://api.example.com")));
+        assert!(bypasses(".example.com", &url("https://example.com")));
+        assert!(!bypasses(".example.com", &url("https://notexample.com")));
+    }
+
+    #[test]
+    fn bypasses_matches_exact_host() {
+        assert!(bypasses("example.com", &url("https://example.com")));
+        assert!(!bypasses("example.com", &url("https://other.com")));
+    }
+
+    #[test]
+    fn bypasses_matches_exact_ip_host() {
+        assert!(bypasses("10.0.0.1", &url("https://10.0.0.1:6443")));
+        assert!(!bypasses("10.0.0.1", &url("https://10.0.0.2:6443")));
+    }
+
+    const BASE: &str = "\
+apiVersion: v1
+kind: Config
+users:
+- name: alice
+  user:
+    auth-provider:
+      name: oidc
+      config:
+        id-token: from-base
+";
+
+    const OVERLAY: &str = "\
+apiVersion: v1
+kind: Config
+users:
+- name: alice
+  user:
+    auth-provider:
+      name: oidc
+      config:
+        id-token: from-overlay
+- name: bob
+  user:
+    auth-provider:
+      name: oidc
+      config:
+        id-token: bob-token
+";
+
+    #[test]
+    fn merge_keeps_first_file_user_and_adds_new_ones() {
+        let mut merged: super::Config = serde_yaml::from_str(BASE).unwrap();
+        merged.merge(serde_yaml::from_str(OVERLAY).unwrap());
+
+        let alice = merged
+            .auth_infos
+            .iter()
+            .find(|n| n.name == "alice")
+            .expect("alice should survive the merge");
+        let token = alice
+            .auth_info
+            .auth_provider
+            .as_ref()
+            .and_then(|ap| ap.config.get("id-token"));
+        assert_eq!(token.map(String::as_str), Some("from-base"));
+        assert!(merged.auth_infos.iter().any(|n| n.name == "bob"));
+    }
+}
@@ -0,0 +1,236 @@
+//! OIDC `auth-provider` token resolution, refresh and write-back.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64;
+use failure::ResultExt;
+use serde_json::Value;
+
+use super::apis::{AuthProviderConfig, Config};
+use super::{configure_proxy, utils};
+use crate::{ErrorKind, Result};
+
+/// Clock skew tolerated when checking an `id-token`'s `exp`.
+const EXPIRY_SKEW_SECS: u64 = 10;
+
+/// A resolved bearer token plus any refreshed material.
+pub struct RefreshedToken {
+    pub id_token: String,
+    pub refresh_token: Option<String>,
+    /// `true` when obtained via refresh rather than reused from cache.
+    pub refreshed: bool,
+}
+
+/// Resolve a bearer token for an `oidc` auth-provider, refreshing an expired cached one.
+///
+/// The refresh honors the configured proxy and timeout, but not extra `root_certs`.
+pub fn token(
+    provider: &AuthProviderConfig,
+    proxy: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<RefreshedToken> {
+    if let Some(id_token) = provider.config.get("id-token") {
+        if !is_expired(id_token) {
+            return Ok(RefreshedToken {
+                id_token: id_token.clone(),
+                refresh_token: None,
+                refreshed: false,
+            });
+        }
+    }
+    refresh(provider, proxy, timeout)
+}
+
+/// Build the HTTP client used for the refresh side-channel, honoring proxy and timeout.
+fn http_client(proxy: Option<&str>, timeout: Option<Duration>) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = configure_proxy(proxy)? {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    Ok(builder
+        .build()
+        .context(ErrorKind::KubeConfig("Unable to build OIDC client".into()))?)
+}
+
+/// Whether the cached `id-token` has expired (within the skew). An undecodable token, or one
+/// whose claims lack `exp`, is treated as expired so we fall through to a refresh.
+fn is_expired(id_token: &str) -> bool {
+    let exp = id_token
+        .split('.')
+        .nth(1)
+        .and_then(|part| base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok())
+        .and_then(|decoded| serde_json::from_slice::<Value>(&decoded).ok())
+        .and_then(|claims| claims.get("exp").and_then(Value::as_u64));
+    let exp = match exp {
+        Some(exp) => exp,
+        None => return true,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    now + EXPIRY_SKEW_SECS >= exp
+}
+
+/// Perform OIDC discovery and exchange the refresh token for a fresh `id-token`.
+fn refresh(
+    provider: &AuthProviderConfig,
+    proxy: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<RefreshedToken> {
+    let issuer = field(provider, "idp-issuer-url")?;
+    let client_id = field(provider, "client-id")?;
+    let refresh_token = field(provider, "refresh-token")?;
+    let client_secret = provider
+        .config
+        .get("client-secret")
+        .cloned()
+        .unwrap_or_default();
+
+    let client = http_client(proxy, timeout)?;
+
+    let discovery = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+    let metadata: Value = client
+        .get(&discovery)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|mut r| r.json())
+        .context(ErrorKind::KubeConfig("OIDC discovery request failed".into()))?;
+    let token_endpoint = metadata
+        .get("token_endpoint")
+        .and_then(Value::as_str)
+        .ok_or_else(|| {
+            ErrorKind::KubeConfig("OIDC discovery document lacked a token_endpoint".into())
+        })?;
+
+    let params = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", client_id),
+        ("client_secret", &client_secret),
+    ];
+    let response: Value = client
+        .post(token_endpoint)
+        .form(&params)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|mut r| r.json())
+        .context(ErrorKind::KubeConfig("OIDC token refresh request failed".into()))?;
+
+    let id_token = response
+        .get("id_token")
+        .and_then(Value::as_str)
+        .ok_or_else(|| {
+            ErrorKind::KubeConfig("OIDC token refresh did not return an id_token".into())
+        })?
+        .to_string();
+    let refresh_token = response
+        .get("refresh_token")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+
+    Ok(RefreshedToken {
+        id_token,
+        refresh_token,
+        refreshed: true,
+    })
+}
+
+/// Look up a required entry from the provider config.
+fn field<'a>(provider: &'a AuthProviderConfig, key: &str) -> Result<&'a String> {
+    provider.config.get(key).ok_or_else(|| {
+        ErrorKind::KubeConfig(format!("oidc auth-provider is missing `{}`", key)).into()
+    })
+}
+
+/// Write the refreshed tokens back into the first kubeconfig that defines the selected user.
+///
+/// Scoped to the resolved user name so accounts sharing one issuer are not cross-contaminated.
+/// The file is rewritten from the parsed `Config`, discarding comments and unmodeled fields.
+pub fn persist(user: &str, refreshed: &RefreshedToken) -> Result<()> {
+    let paths = utils::find_kubeconfig()
+        .context(ErrorKind::KubeConfig("Unable to load file".into()))?;
+
+    for path in &paths {
+        let mut config = match Config::load_config(path) {
+            Ok(config) => config,
+            Err(_) => continue,
+        };
+        let mut dirty = false;
+        for named in &mut config.auth_infos {
+            if named.name != user {
+                continue;
+            }
+            if let Some(ap) = named.auth_info.auth_provider.as_mut() {
+                if ap.name == "oidc" {
+                    ap.config
+                        .insert("id-token".to_string(), refreshed.id_token.clone());
+                    if let Some(refresh_token) = &refreshed.refresh_token {
+                        ap.config
+                            .insert("refresh-token".to_string(), refresh_token.clone());
+                    }
+                    dirty = true;
+                }
+            }
+        }
+        if dirty {
+            let serialized = serde_yaml::to_string(&config)
+                .context(ErrorKind::KubeConfig("Unable to serialize kubeconfig".into()))?;
+            std::fs::write(path, serialized).context(ErrorKind::KubeConfig(
+                "Unable to persist refreshed kubeconfig".into(),
+            ))?;
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_expired;
+    use base64;
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn jwt_with_exp(exp: u64) -> String {
+        let claims = base64::encode_config(json!({ "exp": exp }).to_string(), base64::URL_SAFE_NO_PAD);
+        format!("header.{}.signature", claims)
+    }
+
+    #[test]
+    fn valid_token_is_not_expired() {
+        assert!(!is_expired(&jwt_with_exp(now() + 3600)));
+    }
+
+    #[test]
+    fn past_exp_is_expired() {
+        assert!(is_expired(&jwt_with_exp(now() - 3600)));
+    }
+
+    #[test]
+    fn exp_within_skew_is_expired() {
+        assert!(is_expired(&jwt_with_exp(now() + 5)));
+    }
+
+    #[test]
+    fn missing_exp_claim_is_expired() {
+        let claims = base64::encode_config(json!({ "sub": "a" }).to_string(), base64::URL_SAFE_NO_PAD);
+        assert!(is_expired(&format!("header.{}.signature", claims)));
+    }
+
+    #[test]
+    fn malformed_token_is_expired() {
+        assert!(is_expired("not-a-jwt"));
+        assert!(is_expired("header.%%%notbase64%%%.signature"));
+    }
+}